@@ -1,18 +1,230 @@
-use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use criterion::{
+    black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput,
+};
+use crossbeam_utils::thread::scope;
 
 use zap_demo::lifo::LifoPush;
 
-fn fibonacci(n: u64) -> u64 {
-    match n {
-        0 => 1,
-        1 => 1,
-        n => fibonacci(n-1) + fibonacci(n-2),
+const PRODUCER_COUNTS: [u64; 4] = [1, 2, 4, 8];
+const ITEMS_PER_PRODUCER: u64 = 1_000;
+
+/// Push throughput under contention: `producers` threads each pushing
+/// `ITEMS_PER_PRODUCER` items, compared against a `Mutex<Vec<T>>` doing the
+/// same under a lock.
+fn push_throughput(c: &mut Criterion) {
+    let mut group = c.benchmark_group("push_throughput");
+    for &producers in &PRODUCER_COUNTS {
+        group.throughput(Throughput::Elements(producers * ITEMS_PER_PRODUCER));
+
+        group.bench_with_input(
+            BenchmarkId::new("lifo", producers),
+            &producers,
+            |b, &producers| {
+                b.iter(|| {
+                    let list: LifoPush<u64> = LifoPush::default();
+                    let list_ref = &list;
+                    scope(|s| {
+                        for _ in 0..producers {
+                            s.spawn(move |_| {
+                                for i in 0..ITEMS_PER_PRODUCER {
+                                    list_ref.push(black_box(i));
+                                }
+                            });
+                        }
+                    })
+                    .unwrap();
+                    list
+                });
+            },
+        );
+
+        group.bench_with_input(
+            BenchmarkId::new("mutex_vec", producers),
+            &producers,
+            |b, &producers| {
+                b.iter(|| {
+                    let list: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+                    let list_ref = &list;
+                    scope(|s| {
+                        for _ in 0..producers {
+                            s.spawn(move |_| {
+                                for i in 0..ITEMS_PER_PRODUCER {
+                                    list_ref.lock().unwrap().push(black_box(i));
+                                }
+                            });
+                        }
+                    })
+                    .unwrap();
+                    list
+                });
+            },
+        );
+    }
+    group.finish();
+}
+
+/// Drained-items-per-`pop_all` once `producers` threads have finished
+/// pushing, compared against draining a `Mutex<Vec<T>>` the same way.
+fn drain_throughput(c: &mut Criterion) {
+    let mut group = c.benchmark_group("drain_throughput");
+    for &producers in &PRODUCER_COUNTS {
+        group.throughput(Throughput::Elements(producers * ITEMS_PER_PRODUCER));
+
+        group.bench_with_input(
+            BenchmarkId::new("lifo_pop_all", producers),
+            &producers,
+            |b, &producers| {
+                b.iter_batched(
+                    || {
+                        let list: LifoPush<u64> = LifoPush::default();
+                        let list_ref = &list;
+                        scope(|s| {
+                            for _ in 0..producers {
+                                s.spawn(move |_| {
+                                    for i in 0..ITEMS_PER_PRODUCER {
+                                        list_ref.push(i);
+                                    }
+                                });
+                            }
+                        })
+                        .unwrap();
+                        list
+                    },
+                    |list| {
+                        let mut drained = 0u64;
+                        list.pop_all(|val| {
+                            black_box(val);
+                            drained += 1;
+                        });
+                        drained
+                    },
+                    BatchSize::SmallInput,
+                );
+            },
+        );
+
+        group.bench_with_input(
+            BenchmarkId::new("mutex_vec_drain", producers),
+            &producers,
+            |b, &producers| {
+                b.iter_batched(
+                    || {
+                        let list: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+                        let list_ref = &list;
+                        scope(|s| {
+                            for _ in 0..producers {
+                                s.spawn(move |_| {
+                                    for i in 0..ITEMS_PER_PRODUCER {
+                                        list_ref.lock().unwrap().push(i);
+                                    }
+                                });
+                            }
+                        })
+                        .unwrap();
+                        list
+                    },
+                    |list| list.lock().unwrap().drain(..).count(),
+                    BatchSize::SmallInput,
+                );
+            },
+        );
     }
+    group.finish();
 }
 
-fn criterion_benchmark(c: &mut Criterion) {
-    c.bench_function("lifo", |b| b.iter(|| fibonacci(black_box(20))));
+/// The scenario the `spin_swap`/`pop_all` interaction actually targets:
+/// `producers` threads hammering `push` while one consumer thread calls
+/// `pop_all` in a loop until they're all done, compared against the same
+/// arrangement over a `Mutex<Vec<T>>`.
+fn concurrent_push_pop_all(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent_push_pop_all");
+    for &producers in &PRODUCER_COUNTS {
+        group.throughput(Throughput::Elements(producers * ITEMS_PER_PRODUCER));
+
+        group.bench_with_input(
+            BenchmarkId::new("lifo", producers),
+            &producers,
+            |b, &producers| {
+                b.iter(|| {
+                    let list: LifoPush<u64> = LifoPush::default();
+                    let producers_done = AtomicUsize::new(0);
+                    let drained = AtomicU64::new(0);
+                    scope(|s| {
+                        for _ in 0..producers {
+                            s.spawn(|_| {
+                                for i in 0..ITEMS_PER_PRODUCER {
+                                    list.push(black_box(i));
+                                }
+                                producers_done.fetch_add(1, Ordering::Release);
+                            });
+                        }
+                        s.spawn(|_| {
+                            let drain_once = || {
+                                list.pop_all(|val| {
+                                    black_box(val);
+                                    drained.fetch_add(1, Ordering::Relaxed);
+                                });
+                            };
+                            while producers_done.load(Ordering::Acquire) < producers as usize {
+                                drain_once();
+                            }
+                            // One last pass for whatever was pushed between
+                            // this consumer's last `pop_all` in the loop and
+                            // the final producer marking itself done.
+                            drain_once();
+                        });
+                    })
+                    .unwrap();
+                    drained.into_inner()
+                });
+            },
+        );
+
+        group.bench_with_input(
+            BenchmarkId::new("mutex_vec", producers),
+            &producers,
+            |b, &producers| {
+                b.iter(|| {
+                    let list: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+                    let producers_done = AtomicUsize::new(0);
+                    let drained = AtomicU64::new(0);
+                    scope(|s| {
+                        for _ in 0..producers {
+                            s.spawn(|_| {
+                                for i in 0..ITEMS_PER_PRODUCER {
+                                    list.lock().unwrap().push(black_box(i));
+                                }
+                                producers_done.fetch_add(1, Ordering::Release);
+                            });
+                        }
+                        s.spawn(|_| {
+                            let drain_once = || {
+                                let batch = std::mem::take(&mut *list.lock().unwrap());
+                                drained.fetch_add(batch.len() as u64, Ordering::Relaxed);
+                                black_box(batch);
+                            };
+                            while producers_done.load(Ordering::Acquire) < producers as usize {
+                                drain_once();
+                            }
+                            drain_once();
+                        });
+                    })
+                    .unwrap();
+                    drained.into_inner()
+                });
+            },
+        );
+    }
+    group.finish();
 }
 
-criterion_group!(benches, criterion_benchmark);
-criterion_main!(benches);
\ No newline at end of file
+criterion_group!(
+    benches,
+    push_throughput,
+    drain_throughput,
+    concurrent_push_pop_all
+);
+criterion_main!(benches);
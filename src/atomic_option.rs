@@ -0,0 +1,84 @@
+//! A standalone atomic "optional owned box": the classic `AtomicOption<T>`
+//! that once lived in `std::sync::atomic` before it was removed pre-1.0.
+//!
+//! `crate::lifo::AtomicOptionBox` reinvents a sliver of this, but stays
+//! private and separate rather than being replaced by this type: it's built
+//! around raw-pointer operations (`load_raw`/`store_raw`/`swap_raw`/
+//! `cas_raw`) that `push`/`pop` drive in their own CAS retry loops and
+//! `pop_all` uses for one unconditional swap, not the owning, one-shot
+//! `swap`/`take` this type exposes. This is the
+//! general-purpose building block for code that just wants atomic ownership
+//! hand-off, usable independently of `LifoPush`.
+
+use core::sync::atomic::Ordering;
+
+#[cfg(feature = "portable-atomic")]
+use alloc::boxed::Box;
+
+#[cfg(not(feature = "portable-atomic"))]
+use core::sync::atomic::AtomicPtr;
+
+#[cfg(feature = "portable-atomic")]
+use portable_atomic::AtomicPtr;
+
+fn into_raw<T>(val: Option<Box<T>>) -> *mut T {
+    val.map_or(core::ptr::null_mut(), Box::into_raw)
+}
+
+unsafe fn from_raw<T>(ptr: *mut T) -> Option<Box<T>> {
+    if ptr.is_null() {
+        None
+    } else {
+        Some(Box::from_raw(ptr))
+    }
+}
+
+/// Atomic ownership of an optional heap box: whichever `swap` (or the
+/// `take`/`fill` built on it) observes a given `Box<T>` is the only one that
+/// ever will, so there's never more than one owner to hand it to.
+pub struct AtomicOption<T> {
+    ptr: AtomicPtr<T>,
+}
+
+impl<T> AtomicOption<T> {
+    pub fn new(initial: Option<Box<T>>) -> Self {
+        Self {
+            ptr: AtomicPtr::new(into_raw(initial)),
+        }
+    }
+
+    pub fn is_none(&self) -> bool {
+        self.ptr.load(Ordering::Relaxed).is_null()
+    }
+
+    pub fn swap(&self, new: Option<Box<T>>, order: Ordering) -> Option<Box<T>> {
+        let old = self.ptr.swap(into_raw(new), order);
+        unsafe { from_raw(old) }
+    }
+
+    pub fn take(&self, order: Ordering) -> Option<Box<T>> {
+        self.swap(None, order)
+    }
+
+    pub fn fill(&self, new: Box<T>, order: Ordering) -> Option<Box<T>> {
+        self.swap(Some(new), order)
+    }
+}
+
+impl<T> Default for AtomicOption<T> {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl<T> Drop for AtomicOption<T> {
+    fn drop(&mut self) {
+        drop(self.take(Ordering::Acquire));
+    }
+}
+
+// Safety: `AtomicPtr` gives exclusive, atomic hand-off of the `Box<T>` this
+// owns, so sending or sharing an `AtomicOption<T>` is exactly as safe as
+// sending a `Box<T>` itself.
+unsafe impl<T: Send> Send for AtomicOption<T> {}
+unsafe impl<T: Send> Sync for AtomicOption<T> {}
@@ -0,0 +1,504 @@
+//! Exhaustive interleaving fuzzer for the push/pop_all algorithm in
+//! [`crate::lifo`]. The old `paper_scenario` test just ran the algorithm
+//! under a wall-clock timer and hoped a race would show up; this instead
+//! enumerates every legal execution and lets the caller assert every
+//! outcome is acceptable.
+//!
+//! Each atomic location remembers every value ever written to it. A
+//! `Relaxed` load may return any write not yet established as
+//! happens-before the calling thread (a stale read); an `Acquire` load that
+//! lands on a `Release` store imports that write's position, after which
+//! the thread may never again observe an earlier write -- the causality
+//! floor only rises. Scheduling is just another nondeterministic choice:
+//! "which runnable thread steps next". Every choice, scheduling or value,
+//! is drawn from [`choose`], which is driven by a recorded [`Decision`]
+//! trace; after a run finishes, [`model`] backs up to the last decision
+//! with an unexplored option, bumps it, and replays -- a depth-first walk
+//! of the whole interleaving x stale-read tree.
+//!
+//! This module, and the `AtomicPtr`/`AtomicUsize` swap in [`crate::lifo`]
+//! that routes through it, only exist under `--cfg fuzzing` -- the same way
+//! `crate::lifo`'s loom backend only exists under `--cfg loom`. That means
+//! `tests/fuzz.rs` isn't part of a plain `cargo test`; it needs its own
+//! invocation, e.g. `RUSTFLAGS="--cfg fuzzing" cargo test --test fuzz`.
+
+use std::cell::{Cell, RefCell};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+
+/// Hands out the stable identity used to key a thread's per-location
+/// causality floor. Locations can't use their own address for this: they
+/// live inside a `Mutex` that itself moves into place inside `AtomicPtr`,
+/// so the address at construction time isn't the address it settles at.
+fn next_location_id() -> usize {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    NEXT.fetch_add(1, Ordering::Relaxed) as usize
+}
+
+/// One branch point recorded during a run: how many options were available,
+/// and which one this run took.
+#[derive(Clone, Copy)]
+struct Decision {
+    options: usize,
+    chosen: usize,
+}
+
+struct TraceState {
+    /// Decisions from the previous run, replayed verbatim so this run
+    /// revisits the same prefix.
+    replay: Vec<Decision>,
+    /// Decisions made so far in the run currently executing.
+    trace: Vec<Decision>,
+}
+
+thread_local! {
+    static FLOOR: RefCell<Vec<(usize, usize)>> = RefCell::new(Vec::new());
+}
+
+/// A thread-local, per-location index: the lowest write this thread may
+/// still observe. Locations are identified by the id [`next_location_id`]
+/// handed them at construction.
+fn floor_for(location: usize) -> usize {
+    FLOOR.with(|f| {
+        let f = f.borrow();
+        f.iter()
+            .find_map(|&(loc, idx)| if loc == location { Some(idx) } else { None })
+            .unwrap_or(0)
+    })
+}
+
+fn raise_floor(location: usize, idx: usize) {
+    FLOOR.with(|f| {
+        let mut f = f.borrow_mut();
+        if let Some(entry) = f.iter_mut().find(|(loc, _)| *loc == location) {
+            entry.1 = entry.1.max(idx);
+        } else {
+            f.push((location, idx));
+        }
+    });
+}
+
+/// One entry per OS thread spawned via [`Scope::spawn`]: whether it is still
+/// running user code, or has arrived at a sync point and is waiting to be
+/// granted permission to continue.
+#[derive(Clone, Copy)]
+enum Worker {
+    Running,
+    Waiting { options: usize },
+    Granted { answer: usize },
+    Finished,
+}
+
+struct Control {
+    workers: Vec<Worker>,
+}
+
+/// The control-plane mutex/condvar pair coordinating every spawned thread in
+/// one [`model`] run, plus the [`TraceState`] driving DFS-via-replay.
+struct Runtime {
+    control: Mutex<Control>,
+    cv: Condvar,
+    trace: Mutex<TraceState>,
+}
+
+thread_local! {
+    /// Set for the lifetime of a spawned worker's closure: its id within
+    /// the current [`Runtime`], and a raw pointer to that `Runtime` (the
+    /// runtime outlives every worker, since `model` joins them all before
+    /// returning).
+    static CURRENT: Cell<Option<(*const Runtime, usize)>> = Cell::new(None);
+}
+
+fn with_runtime<R>(f: impl FnOnce(&Runtime, usize) -> R) -> R {
+    CURRENT.with(|c| {
+        let (rt, tid) = c.get().expect("fuzz atomics used outside fuzz::model");
+        // Safety: the runtime is owned by `model`, which blocks until every
+        // spawned worker has returned before it is dropped.
+        f(unsafe { &*rt }, tid)
+    })
+}
+
+/// Draw a value from `0..options`, recording or replaying the choice so the
+/// whole space of choices can be walked exhaustively across repeated runs.
+fn choose(trace: &Mutex<TraceState>, options: usize) -> usize {
+    if options <= 1 {
+        return 0;
+    }
+    let mut state = trace.lock().unwrap();
+    let pos = state.trace.len();
+    let chosen = if pos < state.replay.len() {
+        state.replay[pos].chosen
+    } else {
+        0
+    };
+    state.trace.push(Decision { options, chosen });
+    chosen
+}
+
+/// Block the calling worker at a sync point, announcing how many candidate
+/// outcomes it offers (1 for a plain store), and return the index the
+/// scheduler chose once it grants this worker the turn.
+fn sync_point(rt: &Runtime, tid: usize, options: usize) -> usize {
+    let mut control = rt.control.lock().unwrap();
+    control.workers[tid] = Worker::Waiting { options };
+    rt.cv.notify_all();
+    loop {
+        control = rt.cv.wait(control).unwrap();
+        if let Worker::Granted { answer } = control.workers[tid] {
+            control.workers[tid] = Worker::Running;
+            return answer;
+        }
+    }
+}
+
+fn finish(rt: &Runtime, tid: usize) {
+    let mut control = rt.control.lock().unwrap();
+    control.workers[tid] = Worker::Finished;
+    rt.cv.notify_all();
+}
+
+/// A history of every write made to one atomic location, in the order they
+/// became visible to the run's single active thread.
+struct Location {
+    writes: Vec<(usize, bool)>, // (value, was a Release store)
+}
+
+impl Location {
+    fn new(initial: usize) -> Self {
+        Location {
+            writes: vec![(initial, false)],
+        }
+    }
+
+    fn load(&self, addr: usize, order: Ordering, rt: &Runtime, tid: usize) -> usize {
+        let floor = floor_for(addr);
+        let candidates = self.writes.len() - floor;
+        let choice = sync_point(rt, tid, candidates.max(1));
+        let idx = floor + choice.min(candidates.saturating_sub(1));
+        let (value, released) = self.writes[idx];
+        if order != Ordering::Relaxed && released {
+            raise_floor(addr, idx);
+        }
+        value
+    }
+
+    fn store(&mut self, value: usize, order: Ordering, rt: &Runtime, tid: usize) {
+        sync_point(rt, tid, 1);
+        self.writes.push((value, order != Ordering::Relaxed));
+    }
+
+    /// Models `AtomicPtr::swap`/`AtomicUsize::swap`: a single indivisible
+    /// read-then-write, so -- unlike `load` followed by `store` -- no other
+    /// thread can be scheduled in between.
+    fn swap(
+        &mut self,
+        addr: usize,
+        value: usize,
+        order: Ordering,
+        rt: &Runtime,
+        tid: usize,
+    ) -> usize {
+        let floor = floor_for(addr);
+        let candidates = self.writes.len() - floor;
+        let choice = sync_point(rt, tid, candidates.max(1));
+        let idx = floor + choice.min(candidates.saturating_sub(1));
+        let (previous, released) = self.writes[idx];
+        if order != Ordering::Relaxed && released {
+            raise_floor(addr, idx);
+        }
+        self.writes.push((value, order != Ordering::Relaxed));
+        previous
+    }
+
+    /// Models `compare_exchange_weak`: branches over every stale value this
+    /// load could observe, each twice: once as a normal attempt and once as
+    /// a spurious failure (the "weak" part callers must loop against). For a
+    /// value not equal to `current` both branches fail the same way.
+    fn compare_exchange_weak(
+        &mut self,
+        addr: usize,
+        current: usize,
+        new: usize,
+        success: Ordering,
+        failure: Ordering,
+        rt: &Runtime,
+        tid: usize,
+    ) -> Result<usize, usize> {
+        let floor = floor_for(addr);
+        let candidates = self.writes.len() - floor;
+        // Each candidate stale write doubles into two outcomes: a normal
+        // attempt (which commits only if the value matches `current`), or a
+        // spurious failure the way a real `_weak` CAS may fail even when the
+        // observed value matched `current`.
+        let choice = sync_point(rt, tid, candidates * 2);
+        let seen_idx = floor + (choice / 2).min(candidates.saturating_sub(1));
+        let spurious = choice % 2 == 1;
+        let observed = self.writes[seen_idx].0;
+        if observed == current && !spurious {
+            if self.writes[seen_idx].1 {
+                raise_floor(addr, seen_idx);
+            }
+            self.writes.push((new, success != Ordering::Relaxed));
+            Ok(observed)
+        } else {
+            if failure != Ordering::Relaxed && self.writes[seen_idx].1 {
+                raise_floor(addr, seen_idx);
+            }
+            Err(observed)
+        }
+    }
+}
+
+/// A fuzzed mirror of [`std::sync::atomic::AtomicPtr`]. Pointers are stored
+/// as their `usize` bit pattern so the history-tracked [`Location`] stays
+/// type-agnostic; callers never see the cast.
+pub struct AtomicPtr<T> {
+    addr: usize,
+    location: Mutex<Location>,
+    _marker: std::marker::PhantomData<*mut T>,
+}
+
+impl<T> AtomicPtr<T> {
+    pub fn new(ptr: *mut T) -> Self {
+        AtomicPtr {
+            addr: next_location_id(),
+            location: Mutex::new(Location::new(ptr as usize)),
+            _marker: std::marker::PhantomData,
+        }
+    }
+
+    pub fn load(&self, order: Ordering) -> *mut T {
+        with_runtime(|rt, tid| {
+            self.location
+                .lock()
+                .unwrap()
+                .load(self.addr, order, rt, tid) as *mut T
+        })
+    }
+
+    pub fn swap(&self, ptr: *mut T, order: Ordering) -> *mut T {
+        with_runtime(|rt, tid| {
+            self.location
+                .lock()
+                .unwrap()
+                .swap(self.addr, ptr as usize, order, rt, tid) as *mut T
+        })
+    }
+
+    pub fn store(&self, ptr: *mut T, order: Ordering) {
+        with_runtime(|rt, tid| {
+            self.location
+                .lock()
+                .unwrap()
+                .store(ptr as usize, order, rt, tid)
+        })
+    }
+
+    pub fn compare_exchange_weak(
+        &self,
+        current: *mut T,
+        new: *mut T,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<*mut T, *mut T> {
+        with_runtime(|rt, tid| {
+            self.location
+                .lock()
+                .unwrap()
+                .compare_exchange_weak(
+                    self.addr,
+                    current as usize,
+                    new as usize,
+                    success,
+                    failure,
+                    rt,
+                    tid,
+                )
+                .map(|v| v as *mut T)
+                .map_err(|v| v as *mut T)
+        })
+    }
+}
+
+// Safety: every access goes through `with_runtime`, which hands out the
+// pointee only as a `usize` bit pattern stored behind `Mutex<Location>` --
+// the same reasoning `std::sync::atomic::AtomicPtr` itself relies on to be
+// `Send`/`Sync` regardless of `T`. The raw-pointer `PhantomData<*mut T>`
+// would otherwise make this `!Send`/`!Sync`, which breaks the `F: Send`
+// bound `crossbeam_utils::thread::scope`'s `Scope::spawn` needs.
+unsafe impl<T> Send for AtomicPtr<T> {}
+unsafe impl<T> Sync for AtomicPtr<T> {}
+
+/// A fuzzed mirror of [`std::sync::atomic::AtomicUsize`], sharing the same
+/// history-tracked [`Location`] machinery as [`AtomicPtr`].
+pub struct AtomicUsize {
+    addr: usize,
+    location: Mutex<Location>,
+}
+
+impl AtomicUsize {
+    pub fn new(value: usize) -> Self {
+        AtomicUsize {
+            addr: next_location_id(),
+            location: Mutex::new(Location::new(value)),
+        }
+    }
+
+    pub fn load(&self, order: Ordering) -> usize {
+        with_runtime(|rt, tid| {
+            self.location
+                .lock()
+                .unwrap()
+                .load(self.addr, order, rt, tid)
+        })
+    }
+
+    pub fn store(&self, value: usize, order: Ordering) {
+        with_runtime(|rt, tid| self.location.lock().unwrap().store(value, order, rt, tid))
+    }
+}
+
+/// Spins in the model the way [`std::hint::spin_loop`] does on real
+/// hardware: it performs no atomic operation of its own, so it contributes
+/// no new branch point.
+pub fn spin_loop() {}
+
+/// Handle for registering worker closures with one `model` run. Mirrors the
+/// `crossbeam_utils::thread::scope` shape already used by this crate's
+/// tests, minus the borrow-checking scaffolding it needs for real threads --
+/// everything here runs against one `Runtime`, so plain `'static` closures
+/// are enough.
+pub struct Scope<'a> {
+    rt: &'a Runtime,
+    handles: RefCell<Vec<std::thread::JoinHandle<()>>>,
+}
+
+impl<'a> Scope<'a> {
+    pub fn spawn<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let rt = self.rt as *const Runtime as usize;
+        let mut control = self.rt.control.lock().unwrap();
+        let tid = control.workers.len();
+        control.workers.push(Worker::Running);
+        drop(control);
+        let handle = std::thread::spawn(move || {
+            CURRENT.with(|c| c.set(Some((rt as *const Runtime, tid))));
+            f();
+            with_runtime(|rt, tid| finish(rt, tid));
+        });
+        self.handles.borrow_mut().push(handle);
+    }
+}
+
+fn run_once<R>(
+    replay: Vec<Decision>,
+    body: &dyn Fn(&Scope) -> R,
+    check: &dyn Fn(R),
+) -> Vec<Decision> {
+    let rt = Runtime {
+        control: Mutex::new(Control {
+            workers: Vec::new(),
+        }),
+        cv: Condvar::new(),
+        trace: Mutex::new(TraceState {
+            replay,
+            trace: Vec::new(),
+        }),
+    };
+    let scope = Scope {
+        rt: &rt,
+        handles: RefCell::new(Vec::new()),
+    };
+    let result = body(&scope);
+
+    loop {
+        let control = rt.control.lock().unwrap();
+        let all_done_or_waiting = control
+            .workers
+            .iter()
+            .all(|w| matches!(w, Worker::Waiting { .. } | Worker::Finished));
+        let all_finished = control
+            .workers
+            .iter()
+            .all(|w| matches!(w, Worker::Finished));
+        if all_finished {
+            break;
+        }
+        if all_done_or_waiting {
+            let runnable: Vec<usize> = control
+                .workers
+                .iter()
+                .enumerate()
+                .filter(|(_, w)| matches!(w, Worker::Waiting { .. }))
+                .map(|(i, _)| i)
+                .collect();
+            drop(control);
+            let pick = choose(&rt.trace, runnable.len());
+            let tid = runnable[pick];
+            let mut control = rt.control.lock().unwrap();
+            let options = match control.workers[tid] {
+                Worker::Waiting { options } => options,
+                _ => unreachable!(),
+            };
+            drop(control);
+            let answer = choose(&rt.trace, options);
+            let mut control = rt.control.lock().unwrap();
+            control.workers[tid] = Worker::Granted { answer };
+            rt.cv.notify_all();
+        } else {
+            let _unused = rt.cv.wait(control).unwrap();
+        }
+    }
+
+    for handle in scope.handles.into_inner() {
+        handle.join().unwrap();
+    }
+
+    // Every spawned thread has finished, so it's safe to inspect whatever
+    // state `body` handed back -- this is where the caller asserts the
+    // outcome of this particular interleaving was acceptable.
+    check(result);
+
+    rt.trace.into_inner().unwrap().trace
+}
+
+/// Given the full trace of one completed run, back up to the last decision
+/// with an unexplored option and bump it, yielding the replay prefix for
+/// the next run. Returns `None` once every option at every decision has
+/// been tried -- the whole tree has been walked.
+fn next_replay(mut trace: Vec<Decision>) -> Option<Vec<Decision>> {
+    while let Some(last) = trace.pop() {
+        if last.chosen + 1 < last.options {
+            trace.push(Decision {
+                options: last.options,
+                chosen: last.chosen + 1,
+            });
+            return Some(trace);
+        }
+    }
+    None
+}
+
+/// Run `body` once per reachable interleaving of the atomics it touches,
+/// crossed with every legal stale read each load could observe. `body`
+/// receives a [`Scope`] to spawn worker closures on, mirroring
+/// `crossbeam_utils::thread::scope`; once every spawned worker for that
+/// interleaving has finished, whatever `body` returned is passed to `check`
+/// so the caller can assert that outcome was acceptable. `model` returns
+/// only once every reachable path has been walked and checked.
+pub fn model<F, R, C>(body: F, check: C)
+where
+    F: Fn(&Scope) -> R,
+    C: Fn(R),
+{
+    let mut replay = Vec::new();
+    loop {
+        let trace = run_once(replay, &body, &check);
+        match next_replay(trace) {
+            Some(next) => replay = next,
+            None => break,
+        }
+    }
+}
@@ -0,0 +1,10 @@
+#![cfg_attr(feature = "portable-atomic", no_std)]
+
+#[cfg(feature = "portable-atomic")]
+extern crate alloc;
+
+#[cfg(fuzzing)]
+pub mod fuzz;
+
+pub mod atomic_option;
+pub mod lifo;
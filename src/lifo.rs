@@ -1,62 +1,75 @@
 //! See http://www.open-std.org/jtc1/sc22/wg21/docs/papers/2019/p1726r1.pdf This
 //! implementation doesn't aim for idiomatic rust, rather for being obviously
 //! the same.
+//!
+//! `core`/`alloc` rather than `std` throughout, so this module keeps working
+//! on `no_std` targets once the `portable-atomic` feature swaps in an
+//! `AtomicPtr` that doesn't need native pointer-width CAS.
 
-use std::{
-    hint::spin_loop,
-    marker::PhantomData,
-    sync::atomic::{AtomicPtr, Ordering},
-};
+use core::{marker::PhantomData, mem::ManuallyDrop, sync::atomic::Ordering};
 
-/// AtomicOptionBox-alike but tailored for this algorithm. Conceptually this
-/// owns, or might own a T, and allows interior mutability.
+#[cfg(feature = "portable-atomic")]
+use alloc::boxed::Box;
+
+#[cfg(not(feature = "portable-atomic"))]
+use crossbeam_epoch::Owned;
+
+#[cfg(not(any(loom, fuzzing, feature = "portable-atomic")))]
+use core::sync::atomic::AtomicPtr;
+
+#[cfg(loom)]
+use loom::sync::atomic::AtomicPtr;
+
+#[cfg(fuzzing)]
+use crate::fuzz::AtomicPtr;
+
+#[cfg(feature = "portable-atomic")]
+use portable_atomic::AtomicPtr;
+
+#[cfg(loom)]
+use loom::hint::spin_loop;
+
+#[cfg(fuzzing)]
+use crate::fuzz::spin_loop;
+
+#[cfg(not(any(loom, fuzzing)))]
+use core::hint::spin_loop;
+
+/// `AtomicOption<T>`-alike but tailored for this algorithm: kept private and
+/// separate from [`crate::atomic_option::AtomicOption`] because `push`/`pop`
+/// each run their own CAS retry loop over a raw pointer (`load_raw` then
+/// `cas_raw`, updating what they retry with on failure) and `pop_all` needs
+/// an unconditional `swap_raw` of the whole chain -- none of which
+/// `AtomicOption`'s owning `swap`/`take` API, built around handing over one
+/// `Option<Box<T>>` at a time, exposes. Conceptually this owns, or might own
+/// a T, and allows interior mutability.
 struct AtomicOptionBox<T> {
     ptr: AtomicPtr<T>,
     _marker: PhantomData<T>,
 }
 
 impl<T> AtomicOptionBox<T> {
-    /// *new -> self, self->*current
-    pub fn spin_swap(
-        &self,
-        current: *mut AtomicOptionBox<T>,
-        new: *mut T,
-        success: Ordering,
-        failure: Ordering,
-    ) {
-        loop {
-            match unsafe {
-                self.ptr
-                    .compare_exchange_weak(*(*current).ptr.get_mut(), new, success, failure)
-            } {
-                Ok(_) => break,
-                Err(x) => {
-                    unsafe {
-                        *(*current).ptr.get_mut() = x;
-                    }
-                    spin_loop();
-                }
-            }
-        }
-    }
-
     pub fn is_none(&self) -> bool {
         // Only consider the bits of the pointer
         let ptr = self.ptr.load(Ordering::Relaxed);
         ptr.is_null()
     }
 
+    /// Only used by the `no_std`/`portable-atomic` build of `pop_all`, which
+    /// has no `crossbeam_epoch` to defer frees through and so just takes
+    /// (and later unwraps) ownership of each node directly.
+    #[cfg(feature = "portable-atomic")]
     pub fn take(&self, order: Ordering) -> AtomicOptionBox<T> {
-        let ptr: *mut T = std::ptr::null_mut();
+        let ptr: *mut T = core::ptr::null_mut();
         let p = self.ptr.swap(ptr, order);
-        let p = AtomicPtr::new(p);
         Self {
-            ptr: p,
-            ..Default::default()
+            ptr: AtomicPtr::new(p),
+            _marker: PhantomData,
         }
     }
 
-    pub fn unwrap(&mut self, ordering: Ordering) -> T {
+    #[cfg(feature = "portable-atomic")]
+    pub fn unwrap(self, ordering: Ordering) -> T {
         let ptr = self.ptr.load(ordering);
         if ptr.is_null() {
             panic!("unwrap called on None AtomicOptionBox");
@@ -64,20 +77,60 @@ impl<T> AtomicOptionBox<T> {
             *(unsafe { Box::from_raw(ptr) })
         }
     }
+
+    /// The raw pointer currently stored, without consuming it. Used by
+    /// [`LifoPush::push`] and [`LifoPush::pop`], which each run their own
+    /// CAS retry loop rather than have one built in for them.
+    pub fn load_raw(&self, order: Ordering) -> *mut T {
+        self.ptr.load(order)
+    }
+
+    /// A plain, non-retrying store of the raw pointer. Used by
+    /// [`LifoPush::push`] to record the `next` a not-yet-published node
+    /// should point at before each CAS attempt on `top` -- nothing else can
+    /// observe the node until that CAS publishes it, so there's no
+    /// concurrent write to race against.
+    pub fn store_raw(&self, new: *mut T, order: Ordering) {
+        self.ptr.store(new, order)
+    }
+
+    /// An unconditional swap, returning the raw pointer previously stored.
+    /// Used by [`LifoPush::pop_all`] to take the whole chain as one atomic
+    /// hand-off, the same way [`Self::cas_raw`] lets `pop` take one node.
+    #[cfg(not(feature = "portable-atomic"))]
+    pub fn swap_raw(&self, new: *mut T, order: Ordering) -> *mut T {
+        self.ptr.swap(new, order)
+    }
+
+    /// A single CAS attempt. [`LifoPush::push`] and [`LifoPush::pop`] each
+    /// run their own loop around this so they can update what they retry
+    /// with (`next`/`top`) after a failure.
+    pub fn cas_raw(
+        &self,
+        current: *mut T,
+        new: *mut T,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<*mut T, *mut T> {
+        self.ptr
+            .compare_exchange_weak(current, new, success, failure)
+    }
 }
 
 impl<T> Default for AtomicOptionBox<T> {
     fn default() -> Self {
-        let ptr = AtomicPtr::new(std::ptr::null_mut());
         Self {
-            ptr,
-            ..Default::default()
+            ptr: AtomicPtr::new(core::ptr::null_mut()),
+            _marker: PhantomData,
         }
     }
 }
 
 struct Node<T> {
-    val: T,
+    /// `ManuallyDrop` so [`LifoPush::pop`] can take ownership of `val` by
+    /// raw pointer and defer the rest of the node's destruction to
+    /// `crossbeam_epoch` without that deferred drop double-dropping it.
+    val: ManuallyDrop<T>,
     /// One equivalent to Node *next in C++: Box is a zero-sized heap ownership
     /// abstraction that doesn't have a null equivalent; and Option gives the
     /// nullability aspect.
@@ -87,13 +140,14 @@ struct Node<T> {
 impl<T> Node<T> {
     fn new(val: T) -> Self {
         Self {
-            val,
+            val: ManuallyDrop::new(val),
             next: Default::default(),
         }
     }
 
+    #[cfg(feature = "portable-atomic")]
     fn into_inner(self) -> (AtomicOptionBox<Node<T>>, T) {
-        (self.next, self.val)
+        (self.next, ManuallyDrop::into_inner(self.val))
     }
 }
 
@@ -108,14 +162,59 @@ impl<T> LifoPush<T> {
     }
 
     pub fn push(&self, val: T) {
-        let mut newnode = Box::new(Node::new(val));
-        let current: *mut AtomicOptionBox<Node<T>> = &mut newnode.next;
-        let new: *mut Node<T> = Box::into_raw(newnode);
-        // Release so that the Acquire in list_pop_all can see the contents of newnode.
-        self.top
-            .spin_swap(current, new, Ordering::Release, Ordering::Relaxed);
+        let new: *mut Node<T> = Box::into_raw(Box::new(Node::new(val)));
+        let mut current = self.top.load_raw(Ordering::Relaxed);
+        loop {
+            // Safety: `new` isn't reachable from `top` until the CAS below
+            // publishes it, so nothing else can be reading or writing its
+            // `next` concurrently.
+            unsafe { (*new).next.store_raw(current, Ordering::Relaxed) };
+            // Release so that the Acquire in pop/pop_all can see the
+            // contents of the node this just published.
+            match self
+                .top
+                .cas_raw(current, new, Ordering::Release, Ordering::Relaxed)
+            {
+                Ok(_) => return,
+                Err(x) => {
+                    current = x;
+                    spin_loop();
+                }
+            }
+        }
+    }
+
+    /// `pop` and `pop_all` can run concurrently, so this frees nodes through
+    /// the same epoch guard as [`Self::pop`] rather than freeing each one
+    /// immediately as it's consumed -- otherwise a `pop` that already read a
+    /// node this is in the middle of draining could dereference freed
+    /// memory.
+    #[cfg(not(feature = "portable-atomic"))]
+    pub fn pop_all<F>(&self, mut f: F)
+    where
+        F: FnMut(T),
+    {
+        let guard = crossbeam_epoch::pin();
+        // Acquire so this can see the contents of every node in the chain.
+        let mut head = self.top.swap_raw(core::ptr::null_mut(), Ordering::Acquire);
+        while !head.is_null() {
+            // Safety: mirrors `pop`'s reasoning -- `head` was read while
+            // `guard` is pinned, so even if a concurrent `pop` is
+            // mid-dereference of this same node, the epoch can't advance
+            // far enough to reclaim it before we unpin.
+            let next = unsafe { (*head).next.load_raw(Ordering::Relaxed) };
+            let val = unsafe { ManuallyDrop::take(&mut (*head).val) };
+            let owned = unsafe { Owned::from_raw(head).into_shared(&guard) };
+            unsafe { guard.defer_destroy(owned) };
+            f(val);
+            head = next;
+        }
     }
 
+    /// `no_std`/`portable-atomic` builds have no `crossbeam_epoch` (it needs
+    /// `std`) and no [`Self::pop`] to race against, so this can get away
+    /// with freeing each node as it's consumed.
+    #[cfg(feature = "portable-atomic")]
     pub fn pop_all<F>(&self, mut f: F)
     where
         F: FnMut(T),
@@ -129,52 +228,87 @@ impl<T> LifoPush<T> {
             head = next;
         }
     }
+
+    /// A genuine Treiber-stack pop: unlike `pop_all`, which sidesteps
+    /// reclamation by taking the whole list at once, this removes one node
+    /// at a time while other threads may still be racing it. The naive
+    /// version of that has an ABA problem: between reading `top` and
+    /// CAS-ing it to `top.next`, another thread could pop that same node,
+    /// free it, and have the allocator hand the address back out, so a
+    /// stale CAS would succeed against memory that's since been reused.
+    /// Pinning an epoch for the duration and deferring the free via
+    /// `guard.defer_destroy` closes that: the node is only actually freed
+    /// once every guard pinned before we unlinked it has been dropped, so
+    /// nothing can still be mid-dereference when it happens.
+    #[cfg(not(feature = "portable-atomic"))]
+    pub fn pop(&self) -> Option<T> {
+        let guard = crossbeam_epoch::pin();
+        loop {
+            let head = self.top.load_raw(Ordering::Acquire);
+            if head.is_null() {
+                return None;
+            }
+            // Safety: `head` was read while `guard` is pinned, so even if
+            // another thread unlinks and frees it before our CAS below, the
+            // epoch can't advance far enough to actually reclaim it until
+            // we unpin -- the read of `next` is safe.
+            let next = unsafe { (*head).next.load_raw(Ordering::Relaxed) };
+            match self
+                .top
+                .cas_raw(head, next, Ordering::Release, Ordering::Relaxed)
+            {
+                Ok(_) => {
+                    // Safety: the CAS above is what unlinks `head`, so no
+                    // later `pop`/`pop_all` can observe it again; readers
+                    // that already read it before the CAS are still
+                    // protected by their own pinned guard, and
+                    // `defer_destroy` won't run until the epoch has passed
+                    // all of those too.
+                    let val = unsafe { ManuallyDrop::take(&mut (*head).val) };
+                    let owned = unsafe { Owned::from_raw(head).into_shared(&guard) };
+                    unsafe { guard.defer_destroy(owned) };
+                    return Some(val);
+                }
+                Err(_) => continue,
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{thread, time::Instant};
-
     use crossbeam_utils::thread::scope;
 
     use super::LifoPush;
 
-    fn timed<F: Fn()>(f: F) {
-        let now = Instant::now();
-        while now.elapsed().as_secs() < 10 {
-            println!("{:?}", now.elapsed().as_secs());
-            f()
-        }
+    #[test]
+    fn pop_matches_lifo_order() {
+        let list: LifoPush<i64> = LifoPush::default();
+        list.push(1);
+        list.push(2);
+        list.push(3);
+        assert_eq!(list.pop(), Some(3));
+        assert_eq!(list.pop(), Some(2));
+        assert_eq!(list.pop(), Some(1));
+        assert_eq!(list.pop(), None);
     }
 
     #[test]
-    fn paper_scenario() {
-        // Invalid -language-level- but valid assembly level from the paper:
-        // T1: load top -> var
-        // T2: null->top, top-> processed and free
-        // T1: var -> newnode.next
-        // T2: alloc newnode1 @ old top addr and push to top
-        // T1: CXW : newnode -> top
-        // T2: thread_pop_all; reads newnode then newnode1 then null.
-
-        timed(|| {
-            let list: LifoPush<i64> = super::LifoPush::default();
-            // list.push(45);
-            // scope(|s| {
-            //     s.spawn(|_| {
-            //         list.push(67);
-            //     });
-
-            //     s.spawn(|_| {
-            //         list.pop_all(|_num| {});
-            //         list.push(89);
-            //         let mut acc = 0;
-            //         let acc_ref = &mut acc;
-            //         list.pop_all(|num| *acc_ref += num);
-            //         assert!(acc == 134 || acc == 201);
-            //     });
-            // })
-            // .unwrap();
-        });
+    fn concurrent_pushes_then_pops_lose_or_duplicate_no_items() {
+        let list: LifoPush<i64> = LifoPush::default();
+        let list = &list;
+        scope(|s| {
+            for i in 0..4 {
+                s.spawn(move |_| list.push(i));
+            }
+        })
+        .unwrap();
+
+        let mut popped = Vec::new();
+        while let Some(val) = list.pop() {
+            popped.push(val);
+        }
+        popped.sort_unstable();
+        assert_eq!(popped, vec![0, 1, 2, 3]);
     }
 }
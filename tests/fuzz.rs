@@ -0,0 +1,51 @@
+//! Exhaustively walks the push/push/pop_all scenario from `tests/loom.rs`
+//! through the in-crate `fuzz` model checker instead of loom, so the same
+//! invariant can be checked without an external dependency.
+//!
+//! Gated on `--cfg fuzzing` rather than running under a plain `cargo test`,
+//! for the same reason `tests/loom.rs` is gated on `--cfg loom`: the cfg
+//! also swaps which `AtomicPtr` `crate::lifo` builds against, so this can
+//! only run as its own build. Invoke with
+//! `RUSTFLAGS="--cfg fuzzing" cargo test --test fuzz`.
+#![cfg(fuzzing)]
+
+use std::sync::{Arc, Mutex};
+
+use zap_demo::fuzz::{model, Scope};
+use zap_demo::lifo::LifoPush;
+
+#[test]
+fn push_push_pop_all_is_race_free() {
+    model(
+        |scope: &Scope| {
+            let list = Arc::new(LifoPush::default());
+            let seen = Arc::new(Mutex::new(Vec::new()));
+
+            let pusher_list = Arc::clone(&list);
+            scope.spawn(move || {
+                pusher_list.push(1i64);
+                pusher_list.push(2i64);
+            });
+
+            let popper_list = Arc::clone(&list);
+            let popper_seen = Arc::clone(&seen);
+            scope.spawn(move || {
+                popper_list.pop_all(|val| popper_seen.lock().unwrap().push(val));
+            });
+
+            seen
+        },
+        |seen| {
+            let mut seen = Arc::try_unwrap(seen).unwrap().into_inner().unwrap();
+            seen.sort_unstable();
+            // `push(1)` publishes its node before `push(2)` starts, and the
+            // second node is published already pointing at the first, so
+            // the only reachable outcomes for a single `pop_all` racing both
+            // pushes are {}, {1} or {1, 2}.
+            match seen.as_slice() {
+                [] | [1] | [1, 2] => {}
+                other => panic!("pop_all produced an invalid set: {:?}", other),
+            }
+        },
+    );
+}